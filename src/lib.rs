@@ -1,21 +1,26 @@
-use std::{io, ops::Neg};
+use std::{collections::HashMap, io};
 
 /// Implements our "real" bank.
 #[derive(Debug, Clone, Default)]
 pub struct Bank {
-    accounts: Vec<u64>,
+    accounts: Vec<Account>,
     transactions: Vec<Transaction>,
 }
 
 impl Bank {
-    pub fn open(&mut self, _can_overdraw: bool) -> io::Result<u64> {
-        let new_account = self.accounts.last().map_or(0, |id| *id + 1);
-        self.accounts.push(new_account);
+    pub fn open(&mut self, can_overdraw: bool) -> io::Result<u64> {
+        let new_account = self.accounts.last().map_or(0, |account| account.id + 1);
+        self.accounts.push(Account {
+            id: new_account,
+            can_overdraw,
+        });
 
         Ok(new_account)
     }
 
     pub fn deposit(&mut self, id: u64, amount: u64) -> io::Result<()> {
+        self.ensure_active(id)?;
+
         self.transactions
             .push(Transaction::Deposit { to: id, amount });
 
@@ -23,6 +28,12 @@ impl Bank {
     }
 
     pub fn withdraw(&mut self, id: u64, amount: u64) -> io::Result<()> {
+        self.ensure_active(id)?;
+
+        if !self.can_withdraw(id, amount)? {
+            return Err(overdraw_error(id, amount));
+        }
+
         self.transactions
             .push(Transaction::Withdraw { from: id, amount });
 
@@ -30,36 +41,430 @@ impl Bank {
     }
 
     pub fn transfer(&mut self, from: u64, to: u64, amount: u64) -> io::Result<()> {
+        self.ensure_active(from)?;
+        self.ensure_active(to)?;
+
+        if !self.can_withdraw(from, amount)? {
+            return Err(overdraw_error(from, amount));
+        }
+
         self.transactions
             .push(Transaction::Transfer { from, to, amount });
 
         Ok(())
     }
 
+    /// Moves `amount` from `id`'s free balance into its reserved balance.
+    ///
+    /// Reserving respects the same overdraw/lock policy as a withdrawal, since
+    /// it too reduces the account's free balance.
+    pub fn reserve(&mut self, id: u64, amount: u64) -> io::Result<()> {
+        self.ensure_active(id)?;
+
+        if !self.can_withdraw(id, amount)? {
+            return Err(overdraw_error(id, amount));
+        }
+
+        self.transactions.push(Transaction::Reserve { id, amount });
+
+        Ok(())
+    }
+
+    /// Moves `amount` from `id`'s reserved balance back into its free balance.
+    pub fn unreserve(&mut self, id: u64, amount: u64) -> io::Result<()> {
+        self.ensure_active(id)?;
+
+        if self.reserved_balance(id)? < saturating_i64(amount) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("account {id} does not have {amount} reserved"),
+            ));
+        }
+
+        self.transactions
+            .push(Transaction::Unreserve { id, amount });
+
+        Ok(())
+    }
+
+    /// Pins at least `amount` of `id`'s free balance until [`Bank::remove_lock`]
+    /// is called with the same `lock_id`.
+    ///
+    /// Mirrors Substrate's `LockableCurrency`: locks overlay rather than stack,
+    /// so setting a lock only ever replaces the previous amount held under
+    /// `lock_id`, and the account's reducible balance is governed by the
+    /// largest of its current locks.
+    pub fn set_lock(&mut self, id: u64, lock_id: u64, amount: u64) -> io::Result<()> {
+        self.account(id)?;
+
+        self.transactions
+            .push(Transaction::SetLock { id, lock_id, amount });
+
+        Ok(())
+    }
+
+    pub fn remove_lock(&mut self, id: u64, lock_id: u64) -> io::Result<()> {
+        self.account(id)?;
+
+        self.transactions
+            .push(Transaction::RemoveLock { id, lock_id });
+
+        Ok(())
+    }
+
+    /// Creates `amount` out of thin air and credits it to `to`'s free balance,
+    /// increasing [`Bank::total_issuance`].
+    pub fn mint(&mut self, to: u64, amount: u64) -> io::Result<()> {
+        self.ensure_active(to)?;
+
+        self.transactions.push(Transaction::Mint { to, amount });
+
+        Ok(())
+    }
+
+    /// Destroys `amount` from `from`'s free balance, decreasing
+    /// [`Bank::total_issuance`]. Subject to the same overdraw/lock policy as
+    /// a withdrawal.
+    pub fn burn(&mut self, from: u64, amount: u64) -> io::Result<()> {
+        self.ensure_active(from)?;
+
+        if !self.can_withdraw(from, amount)? {
+            return Err(overdraw_error(from, amount));
+        }
+
+        self.transactions.push(Transaction::Burn { from, amount });
+
+        Ok(())
+    }
+
+    /// Freezes `id`, rejecting any further deposit/withdrawal/transfer/
+    /// reserve/unreserve/mint/burn until it is [`Bank::unfreeze`]d.
+    pub fn freeze(&mut self, id: u64) -> io::Result<()> {
+        self.ensure_active(id)?;
+
+        self.transactions.push(Transaction::Freeze { id });
+
+        Ok(())
+    }
+
+    /// Reactivates a previously [`Bank::freeze`]n account.
+    pub fn unfreeze(&mut self, id: u64) -> io::Result<()> {
+        if self.status(id)? != AccountStatus::Frozen {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("account {id} is not frozen"),
+            ));
+        }
+
+        self.transactions.push(Transaction::Unfreeze { id });
+
+        Ok(())
+    }
+
+    /// Closes `id` for good, like Substrate's dust-account reaping.
+    ///
+    /// Only permitted on an active account with a zero balance, so closing
+    /// can never destroy funds.
+    pub fn close(&mut self, id: u64) -> io::Result<()> {
+        self.ensure_active(id)?;
+
+        if self.balance(id)? != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("account {id} must be empty to close"),
+            ));
+        }
+
+        self.transactions.push(Transaction::Close { id });
+
+        Ok(())
+    }
+
+    /// The total amount of currency in existence, i.e. the sum of all
+    /// [`Bank::mint`]s minus all [`Bank::burn`]s.
+    ///
+    /// This is tracked independently of account balances: transfers,
+    /// reserves and locks only move currency between accounts or between an
+    /// account's free and reserved balance, but [`Bank::deposit`] and
+    /// [`Bank::withdraw`] create and destroy currency without going through
+    /// mint/burn, so this does *not* equal the sum of all account balances.
+    pub fn total_issuance(&self) -> i64 {
+        self.transactions
+            .iter()
+            .fold(0i64, |issuance, transaction| match transaction {
+                Transaction::Mint { amount, .. } => issuance.saturating_add(saturating_i64(*amount)),
+                Transaction::Burn { amount, .. } => issuance.saturating_sub(saturating_i64(*amount)),
+                _ => issuance,
+            })
+    }
+
+    /// The account's total balance, i.e. its free balance plus its reserved balance.
     pub fn balance(&self, id: u64) -> io::Result<i64> {
-        let balance = self
+        self.account(id)?;
+
+        let ledger = self.fold(id);
+
+        Ok(ledger.free.saturating_add(ledger.reserved))
+    }
+
+    /// The portion of the account's balance that is not reserved. This still
+    /// includes any funds pinned by locks.
+    pub fn free_balance(&self, id: u64) -> io::Result<i64> {
+        self.account(id)?;
+
+        Ok(self.fold(id).free)
+    }
+
+    /// The portion of the account's balance that has been set aside via [`Bank::reserve`].
+    pub fn reserved_balance(&self, id: u64) -> io::Result<i64> {
+        self.account(id)?;
+
+        Ok(self.fold(id).reserved)
+    }
+
+    /// Whether `amount` can be withdrawn from `id`'s free balance without
+    /// overdrawing it or dipping below its locked amount.
+    ///
+    /// Accounts opened with `can_overdraw = true` may always withdraw, mirroring
+    /// Substrate's `can_withdraw` / `WithdrawConsequence` checks; all other
+    /// accounts must keep their reducible balance (free balance minus the
+    /// largest current lock) non-negative.
+    fn can_withdraw(&self, id: u64, amount: u64) -> io::Result<bool> {
+        let account = self.account(id)?;
+        if account.can_overdraw {
+            return Ok(true);
+        }
+
+        let ledger = self.fold(id);
+        let amount = saturating_i64(amount);
+
+        Ok(match ledger.free.checked_sub(amount) {
+            Some(remaining) => remaining >= ledger.locked(),
+            None => false,
+        })
+    }
+
+    fn account(&self, id: u64) -> io::Result<&Account> {
+        self.accounts
+            .iter()
+            .find(|account| account.id == id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("unknown account {id}")))
+    }
+
+    /// The account's current lifecycle status, derived from the latest
+    /// `Freeze`/`Unfreeze`/`Close` transaction recorded for it.
+    pub fn status(&self, id: u64) -> io::Result<AccountStatus> {
+        self.account(id)?;
+
+        let status = self
             .transactions
             .iter()
-            .filter_map(|t| match t {
-                Transaction::Deposit { to, amount } => (*to == id).then_some(*amount as i64),
-                Transaction::Withdraw { from, amount } => {
-                    (*from == id).then_some((*amount as i64).neg())
+            .fold(AccountStatus::Active, |status, transaction| match transaction {
+                Transaction::Freeze { id: account } if *account == id => AccountStatus::Frozen,
+                Transaction::Unfreeze { id: account } if *account == id => AccountStatus::Active,
+                Transaction::Close { id: account } if *account == id => AccountStatus::Closed,
+                _ => status,
+            });
+
+        Ok(status)
+    }
+
+    /// Rejects the operation unless `id` is an open, active account.
+    fn ensure_active(&self, id: u64) -> io::Result<()> {
+        match self.status(id)? {
+            AccountStatus::Active => Ok(()),
+            AccountStatus::Frozen => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("account {id} is frozen"),
+            )),
+            AccountStatus::Closed => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("account {id} is closed"),
+            )),
+        }
+    }
+
+    /// Replays the transaction log in the order it was recorded, accumulating
+    /// the free balance, reserved balance and current locks of a single
+    /// account.
+    fn fold(&self, id: u64) -> Ledger {
+        let natural_order: Vec<usize> = (0..self.transactions.len()).collect();
+
+        self.fold_in_order(id, &natural_order)
+            .expect("replaying transactions in their recorded order is always valid")
+    }
+
+    /// Replays the transaction log in the order given by `order` -- a
+    /// permutation of indices into `self.transactions` -- accumulating a
+    /// single account's free balance, reserved balance and current locks.
+    ///
+    /// Unlike [`Bank::fold`], `order` need not match the order transactions
+    /// were recorded in, so an operation that moved money out of the account
+    /// may now run before the deposit that funded it. When that makes the
+    /// account's policy unsatisfiable, this returns an `Err` identifying the
+    /// offending transaction instead of an incorrect balance.
+    fn fold_in_order(&self, id: u64, order: &[usize]) -> io::Result<Ledger> {
+        let account = self.account(id)?;
+        let mut ledger = Ledger::default();
+
+        for &index in order {
+            let transaction = self.transactions.get(index).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("no transaction at index {index}"),
+                )
+            })?;
+
+            match transaction {
+                Transaction::Deposit { to, amount } if *to == id => {
+                    ledger.free = ledger.free.saturating_add(saturating_i64(*amount));
+                }
+                Transaction::Mint { to, amount } if *to == id => {
+                    ledger.free = ledger.free.saturating_add(saturating_i64(*amount));
+                }
+                Transaction::Withdraw { from, amount }
+                    if *from == id && !ledger.try_withdraw(*amount, account.can_overdraw) =>
+                {
+                    return Err(order_violation_error(id, index));
+                }
+                Transaction::Burn { from, amount }
+                    if *from == id && !ledger.try_withdraw(*amount, account.can_overdraw) =>
+                {
+                    return Err(order_violation_error(id, index));
                 }
                 Transaction::Transfer { from, to, amount } => {
-                    if *from == id {
-                        return Some((*amount as i64).neg());
+                    if *from == id && !ledger.try_withdraw(*amount, account.can_overdraw) {
+                        return Err(order_violation_error(id, index));
                     }
-
                     if *to == id {
-                        return Some(*amount as i64);
+                        ledger.free = ledger.free.saturating_add(saturating_i64(*amount));
                     }
+                }
+                Transaction::Reserve { id: account_id, amount } if *account_id == id => {
+                    if !ledger.try_withdraw(*amount, account.can_overdraw) {
+                        return Err(order_violation_error(id, index));
+                    }
+                    ledger.reserved = ledger.reserved.saturating_add(saturating_i64(*amount));
+                }
+                Transaction::Unreserve { id: account_id, amount } if *account_id == id => {
+                    let amount = saturating_i64(*amount);
 
-                    None
+                    if ledger.reserved < amount {
+                        return Err(order_violation_error(id, index));
+                    }
+                    ledger.reserved -= amount;
+                    ledger.free = ledger.free.saturating_add(amount);
                 }
-            })
-            .sum();
+                Transaction::SetLock {
+                    id: account_id,
+                    lock_id,
+                    amount,
+                } if *account_id == id => {
+                    ledger.locks.insert(*lock_id, *amount);
+                }
+                Transaction::RemoveLock {
+                    id: account_id,
+                    lock_id,
+                } if *account_id == id => {
+                    ledger.locks.remove(lock_id);
+                }
+                _ => {}
+            }
+        }
+
+        Ok(ledger)
+    }
+
+    /// Replays `id`'s history in the order given by `order` -- a permutation
+    /// of indices into the transactions recorded so far -- and returns the
+    /// resulting balance.
+    ///
+    /// Inspired by Solana's `OrderedIterator`: pure deposits/mints are
+    /// commutative and agree under any permutation, but an operation that can
+    /// fail (a withdrawal, transfer, reserve, unreserve or burn) may have been
+    /// recorded only because enough was available *at the time it ran*.
+    /// Replaying it before the deposit (or reserve) that funded it is flagged with an `Err` rather than
+    /// silently producing a balance that the real bank would never have
+    /// allowed.
+    pub fn balance_in_order(&self, id: u64, order: &[usize]) -> io::Result<i64> {
+        let ledger = self.fold_in_order(id, order)?;
+
+        Ok(ledger.free.saturating_add(ledger.reserved))
+    }
+}
+
+fn overdraw_error(id: u64, amount: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("withdrawing {amount} from account {id} would overdraw it"),
+    )
+}
+
+fn order_violation_error(id: u64, index: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("replaying transaction {index} out of order would violate account {id}'s policy"),
+    )
+}
+
+/// Converts `amount` to an `i64`, saturating to `i64::MAX` instead of
+/// wrapping negative for values above it.
+///
+/// Balances and locks are tracked as `i64`, so an `amount` near `u64::MAX`
+/// must not be cast directly -- `u64::MAX as i64` is `-1`, which would turn a
+/// withdrawal of an enormous amount into a free balance *increase*.
+fn saturating_i64(amount: u64) -> i64 {
+    i64::try_from(amount).unwrap_or(i64::MAX)
+}
+
+/// An open account and the policy it was opened with.
+#[derive(Debug, Clone)]
+struct Account {
+    id: u64,
+    can_overdraw: bool,
+}
+
+/// An account's lifecycle state: `Active` -> `Frozen` -> `Active`, and
+/// `Active` -> `Closed` once its balance is zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccountStatus {
+    Active,
+    Frozen,
+    Closed,
+}
+
+/// The free balance, reserved balance and locks derived by folding the
+/// transaction log for a single account.
+#[derive(Debug, Default)]
+struct Ledger {
+    free: i64,
+    reserved: i64,
+    locks: HashMap<u64, u64>,
+}
+
+impl Ledger {
+    /// The floor below which the free balance must not fall: the largest of
+    /// the account's current locks, since locks overlay rather than stack.
+    fn locked(&self) -> i64 {
+        saturating_i64(self.locks.values().copied().max().unwrap_or(0))
+    }
+
+    /// Moves `amount` out of the free balance if `can_overdraw` is set or
+    /// doing so would not dip below the locked floor. Returns whether the
+    /// withdrawal happened.
+    fn try_withdraw(&mut self, amount: u64, can_overdraw: bool) -> bool {
+        let amount = saturating_i64(amount);
+
+        if !can_overdraw {
+            match self.free.checked_sub(amount) {
+                Some(remaining) if remaining >= self.locked() => {}
+                _ => return false,
+            }
+        }
+
+        self.free = self.free.saturating_sub(amount);
 
-        Ok(balance)
+        true
     }
 }
 
@@ -68,7 +473,51 @@ impl Bank {
 /// It is typical for ledgers to implemented as a series of transactions.
 #[derive(Debug, Clone)]
 enum Transaction {
-    Deposit { to: u64, amount: u64 },
-    Withdraw { from: u64, amount: u64 },
-    Transfer { from: u64, to: u64, amount: u64 },
+    Deposit {
+        to: u64,
+        amount: u64,
+    },
+    Withdraw {
+        from: u64,
+        amount: u64,
+    },
+    Transfer {
+        from: u64,
+        to: u64,
+        amount: u64,
+    },
+    Reserve {
+        id: u64,
+        amount: u64,
+    },
+    Unreserve {
+        id: u64,
+        amount: u64,
+    },
+    SetLock {
+        id: u64,
+        lock_id: u64,
+        amount: u64,
+    },
+    RemoveLock {
+        id: u64,
+        lock_id: u64,
+    },
+    Mint {
+        to: u64,
+        amount: u64,
+    },
+    Burn {
+        from: u64,
+        amount: u64,
+    },
+    Freeze {
+        id: u64,
+    },
+    Unfreeze {
+        id: u64,
+    },
+    Close {
+        id: u64,
+    },
 }
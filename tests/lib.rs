@@ -1,11 +1,125 @@
+use std::{collections::HashMap, io};
+
 use proptest::{
     arbitrary::any,
-    sample,
+    prop_assert, prop_assert_eq, proptest, sample,
     strategy::{Just, Strategy},
     test_runner::Config,
 };
 use proptest_state_machine::{prop_state_machine, ReferenceStateMachine, StateMachineTest};
-use proptest_state_machine_banking::Bank;
+use proptest_state_machine_banking::{AccountStatus as RealAccountStatus, Bank};
+
+/// A single-account operation used to build the randomized transaction
+/// sequences in [`replaying_transactions_in_any_order_never_silently_disagrees`].
+///
+/// Deposits are commutative; withdrawals, reserves and burns are only
+/// order-sensitive when an earlier deposit funded them.
+#[derive(Debug, Clone)]
+enum Op {
+    Deposit(u64),
+    Withdraw(u64),
+    Reserve(u64),
+    Burn(u64),
+}
+
+impl Op {
+    fn apply(&self, bank: &mut Bank, id: u64) -> io::Result<()> {
+        match self {
+            Op::Deposit(amount) => bank.deposit(id, *amount),
+            Op::Withdraw(amount) => bank.withdraw(id, *amount),
+            Op::Reserve(amount) => bank.reserve(id, *amount),
+            Op::Burn(amount) => bank.burn(id, *amount),
+        }
+    }
+}
+
+/// A random sequence of [`Op`]s paired with several independently-sampled
+/// permutations of its indices.
+///
+/// Each permutation is generated as one [`sample::Index`] per op, wide
+/// enough to cover the whole sequence even though, at apply time, only the
+/// ops that actually succeed end up recorded as transactions -- see
+/// [`permutation_of`].
+fn ops_and_permutations() -> impl Strategy<Value = (Vec<Op>, Vec<Vec<sample::Index>>)> {
+    let op = proptest::prop_oneof![
+        (0u64..50).prop_map(Op::Deposit),
+        (0u64..50).prop_map(Op::Withdraw),
+        (0u64..50).prop_map(Op::Reserve),
+        (0u64..50).prop_map(Op::Burn),
+    ];
+
+    proptest::collection::vec(op, 1..15).prop_flat_map(|ops| {
+        let len = ops.len();
+        let permutations = proptest::collection::vec(proptest::collection::vec(any::<sample::Index>(), len), 5);
+
+        (Just(ops), permutations)
+    })
+}
+
+/// Turns `indices` -- one [`sample::Index`] per op in the original sequence
+/// -- into a permutation of `0..len` via a Fisher-Yates shuffle.
+///
+/// Only the first `len` indices are consumed, so this still works when
+/// fewer than `indices.len()` ops actually succeeded and were recorded.
+fn permutation_of(indices: &[sample::Index], len: usize) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..len).collect();
+
+    for (i, index) in indices.iter().enumerate().take(len) {
+        let j = i + index.index(len - i);
+        order.swap(i, j);
+    }
+
+    order
+}
+
+proptest! {
+    /// Draws a random sequence of deposits, withdrawals, reserves and burns
+    /// on a single account and several random permutations of it, then
+    /// checks that replaying any permutation either agrees with the real
+    /// balance or is flagged as an invalid, order-sensitive replay -- never
+    /// a silent, incorrect balance.
+    ///
+    /// A sequence of pure deposits can never produce a flagged permutation,
+    /// so this subsumes the commutative case; a sequence where a
+    /// withdrawal/reserve/burn only succeeded because of an earlier deposit
+    /// exercises the order-sensitive case.
+    #[test]
+    fn replaying_transactions_in_any_order_never_silently_disagrees(
+        (ops, permutations) in ops_and_permutations(),
+    ) {
+        let mut bank = Bank::default();
+        let id = bank.open(false).unwrap();
+
+        let mut recorded = 0;
+        for op in &ops {
+            if op.apply(&mut bank, id).is_ok() {
+                recorded += 1;
+            }
+        }
+
+        let expected_balance = bank.balance(id).unwrap();
+        let natural_order: Vec<usize> = (0..recorded).collect();
+
+        prop_assert_eq!(bank.balance_in_order(id, &natural_order).unwrap(), expected_balance);
+
+        let only_deposits = ops.iter().all(|op| matches!(op, Op::Deposit(_)));
+
+        for indices in &permutations {
+            let order = permutation_of(indices, recorded);
+
+            match bank.balance_in_order(id, &order) {
+                Ok(balance) => prop_assert_eq!(
+                    balance, expected_balance,
+                    "a permutation that replays validly must agree with the real balance, order {:?}", order
+                ),
+                Err(e) => prop_assert!(
+                    !only_deposits,
+                    "a permutation of pure deposits must never be flagged, order {:?}: {}", order, e
+                ),
+            }
+        }
+    }
+}
 
 prop_state_machine! {
     #![proptest_config(Config {
@@ -25,6 +139,13 @@ prop_state_machine! {
     );
 }
 
+/// Converts `amount` to an `i64`, saturating to `i64::MAX` instead of
+/// wrapping negative for values above it -- mirrors the saturating
+/// conversion the production `Bank` uses for the same reason.
+fn saturating_i64(amount: u64) -> i64 {
+    i64::try_from(amount).unwrap_or(i64::MAX)
+}
+
 /// Holds the state of our "simulated" bank.
 ///
 /// This implements the expected logic using a simplified model and is used to check correctness of the real code.
@@ -33,16 +154,40 @@ struct SimBank {
     accounts: Vec<SimAccount>,
 
     next_account_id: u64,
+
+    total_issuance: i64,
 }
 
 /// A simulated account.
 ///
-/// This holds - contrary to our production code - the actual balance.
+/// This holds - contrary to our production code - the actual balances and
+/// locks directly, rather than deriving them from a transaction log.
 /// This is a much simpler model to assert against.
 #[derive(Debug, Clone)]
 struct SimAccount {
     id: u64,
-    balance: i64,
+    free: i64,
+    reserved: i64,
+    locks: HashMap<u64, u64>,
+    can_overdraw: bool,
+    status: AccountStatus,
+}
+
+impl SimAccount {
+    /// The largest lock currently held on this account, i.e. the floor below
+    /// which the free balance must not fall (locks overlay rather than stack).
+    fn locked(&self) -> i64 {
+        saturating_i64(self.locks.values().copied().max().unwrap_or(0))
+    }
+}
+
+/// Mirrors `Bank`'s account lifecycle: `Active` -> `Frozen` -> `Active`, and
+/// `Active` -> `Closed` once the balance is zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AccountStatus {
+    Active,
+    Frozen,
+    Closed,
 }
 
 /// Holds the state of a "real" bank.
@@ -57,7 +202,9 @@ struct RealBank {
 
 #[derive(Debug, Clone)]
 enum Transition {
-    Open,
+    Open {
+        can_overdraw: bool,
+    },
     Withdraw {
         account_id: sample::Index,
         amount: u64,
@@ -71,15 +218,40 @@ enum Transition {
         to: sample::Index,
         amount: u64,
     },
-    // Freeze {
-    //     account_id: sample::Index,
-    // },
-    // Unfreeze {
-    //     account_id: sample::Index,
-    // },
-    // Close {
-    //     account_id: sample::Index,
-    // },
+    Reserve {
+        account_id: sample::Index,
+        amount: u64,
+    },
+    Unreserve {
+        account_id: sample::Index,
+        amount: u64,
+    },
+    SetLock {
+        account_id: sample::Index,
+        lock_id: u64,
+        amount: u64,
+    },
+    RemoveLock {
+        account_id: sample::Index,
+        lock_id: u64,
+    },
+    Mint {
+        account_id: sample::Index,
+        amount: u64,
+    },
+    Burn {
+        account_id: sample::Index,
+        amount: u64,
+    },
+    Freeze {
+        account_id: sample::Index,
+    },
+    Unfreeze {
+        account_id: sample::Index,
+    },
+    Close {
+        account_id: sample::Index,
+    },
 }
 
 impl ReferenceStateMachine for SimBank {
@@ -90,41 +262,154 @@ impl ReferenceStateMachine for SimBank {
         Just(Self {
             accounts: Default::default(),
             next_account_id: 0,
+            total_issuance: 0,
         })
         .boxed()
     }
 
     fn transitions(_: &Self::State) -> proptest::prelude::BoxedStrategy<Self::Transition> {
         proptest::prop_oneof![
-            Just(Transition::Open),
+            any::<bool>().prop_map(|can_overdraw| Transition::Open { can_overdraw }),
             (any::<sample::Index>(), any::<u64>())
                 .prop_map(|(account_id, amount)| { Transition::Deposit { account_id, amount } }),
             (any::<sample::Index>(), any::<u64>())
                 .prop_map(|(account_id, amount)| { Transition::Withdraw { account_id, amount } }),
             (any::<sample::Index>(), any::<sample::Index>(), any::<u64>())
                 .prop_map(|(from, to, amount)| Transition::Transfer { from, to, amount }),
+            (any::<sample::Index>(), any::<u64>())
+                .prop_map(|(account_id, amount)| { Transition::Reserve { account_id, amount } }),
+            (any::<sample::Index>(), any::<u64>())
+                .prop_map(|(account_id, amount)| { Transition::Unreserve { account_id, amount } }),
+            (any::<sample::Index>(), any::<u64>(), any::<u64>()).prop_map(
+                |(account_id, lock_id, amount)| {
+                    Transition::SetLock {
+                        account_id,
+                        lock_id,
+                        amount,
+                    }
+                }
+            ),
+            (any::<sample::Index>(), any::<u64>())
+                .prop_map(|(account_id, lock_id)| Transition::RemoveLock {
+                    account_id,
+                    lock_id
+                }),
+            (any::<sample::Index>(), any::<u64>())
+                .prop_map(|(account_id, amount)| { Transition::Mint { account_id, amount } }),
+            (any::<sample::Index>(), any::<u64>())
+                .prop_map(|(account_id, amount)| { Transition::Burn { account_id, amount } }),
+            any::<sample::Index>().prop_map(|account_id| Transition::Freeze { account_id }),
+            any::<sample::Index>().prop_map(|account_id| Transition::Unfreeze { account_id }),
+            any::<sample::Index>().prop_map(|account_id| Transition::Close { account_id }),
         ]
         .boxed()
     }
 
     fn apply(mut state: Self::State, transition: &Self::Transition) -> Self::State {
         match transition {
-            Transition::Open => {
+            Transition::Open { can_overdraw } => {
                 state.accounts.push(SimAccount {
-                    balance: 0,
                     id: state.next_account_id,
+                    free: 0,
+                    reserved: 0,
+                    locks: HashMap::new(),
+                    can_overdraw: *can_overdraw,
+                    status: AccountStatus::Active,
                 });
                 state.next_account_id += 1;
             }
             Transition::Withdraw { account_id, amount } => {
-                account_id.get_mut(&mut state.accounts).balance -= (*amount) as i64;
+                let amount = saturating_i64(*amount);
+                let account = account_id.get_mut(&mut state.accounts);
+                if account.can_overdraw || account.free.saturating_sub(amount) >= account.locked() {
+                    account.free = account.free.saturating_sub(amount);
+                }
             }
             Transition::Deposit { account_id, amount } => {
-                account_id.get_mut(&mut state.accounts).balance += (*amount) as i64;
+                let amount = saturating_i64(*amount);
+                let account = account_id.get_mut(&mut state.accounts);
+                account.free = account.free.saturating_add(amount);
             }
             Transition::Transfer { from, to, amount } => {
-                from.get_mut(&mut state.accounts).balance -= (*amount) as i64;
-                to.get_mut(&mut state.accounts).balance += (*amount) as i64;
+                let amount = saturating_i64(*amount);
+                let can_withdraw = {
+                    let from_account = from.get(&state.accounts);
+                    from_account.can_overdraw
+                        || from_account.free.saturating_sub(amount) >= from_account.locked()
+                };
+
+                if can_withdraw {
+                    let from_account = from.get_mut(&mut state.accounts);
+                    from_account.free = from_account.free.saturating_sub(amount);
+
+                    let to_account = to.get_mut(&mut state.accounts);
+                    to_account.free = to_account.free.saturating_add(amount);
+                }
+            }
+            Transition::Reserve { account_id, amount } => {
+                let amount = saturating_i64(*amount);
+                let account = account_id.get_mut(&mut state.accounts);
+                if account.can_overdraw || account.free.saturating_sub(amount) >= account.locked() {
+                    account.free = account.free.saturating_sub(amount);
+                    account.reserved = account.reserved.saturating_add(amount);
+                }
+            }
+            Transition::Unreserve { account_id, amount } => {
+                let amount = saturating_i64(*amount);
+                let account = account_id.get_mut(&mut state.accounts);
+                if account.reserved >= amount {
+                    account.reserved -= amount;
+                    account.free = account.free.saturating_add(amount);
+                }
+            }
+            Transition::SetLock {
+                account_id,
+                lock_id,
+                amount,
+            } => {
+                account_id
+                    .get_mut(&mut state.accounts)
+                    .locks
+                    .insert(*lock_id, *amount);
+            }
+            Transition::RemoveLock {
+                account_id,
+                lock_id,
+            } => {
+                account_id.get_mut(&mut state.accounts).locks.remove(lock_id);
+            }
+            Transition::Mint { account_id, amount } => {
+                let amount = saturating_i64(*amount);
+                let account = account_id.get_mut(&mut state.accounts);
+                account.free = account.free.saturating_add(amount);
+                state.total_issuance = state.total_issuance.saturating_add(amount);
+            }
+            Transition::Burn { account_id, amount } => {
+                let amount = saturating_i64(*amount);
+                let account = account_id.get_mut(&mut state.accounts);
+                if account.can_overdraw || account.free.saturating_sub(amount) >= account.locked() {
+                    account.free = account.free.saturating_sub(amount);
+                    state.total_issuance = state.total_issuance.saturating_sub(amount);
+                }
+            }
+            Transition::Freeze { account_id } => {
+                let account = account_id.get_mut(&mut state.accounts);
+                if account.status == AccountStatus::Active {
+                    account.status = AccountStatus::Frozen;
+                }
+            }
+            Transition::Unfreeze { account_id } => {
+                let account = account_id.get_mut(&mut state.accounts);
+                if account.status == AccountStatus::Frozen {
+                    account.status = AccountStatus::Active;
+                }
+            }
+            Transition::Close { account_id } => {
+                let account = account_id.get_mut(&mut state.accounts);
+                if account.status == AccountStatus::Active && account.free.saturating_add(account.reserved) == 0
+                {
+                    account.status = AccountStatus::Closed;
+                }
             }
         }
 
@@ -132,23 +417,55 @@ impl ReferenceStateMachine for SimBank {
     }
 
     fn preconditions(state: &Self::State, transition: &Self::Transition) -> bool {
+        let is_active = |account_id: &sample::Index| {
+            account_id.get(&state.accounts).status == AccountStatus::Active
+        };
+
         match transition {
-            Transition::Open => true,
-            Transition::Withdraw { .. } => !state.accounts.is_empty(),
-            Transition::Deposit { .. } => !state.accounts.is_empty(),
+            Transition::Open { .. } => true,
+            Transition::Withdraw { account_id, .. } => {
+                !state.accounts.is_empty() && is_active(account_id)
+            }
+            Transition::Deposit { account_id, .. } => {
+                !state.accounts.is_empty() && is_active(account_id)
+            }
             Transition::Transfer { from, to, .. } => {
-                let have_accounts = !state.accounts.is_empty();
-
-                if !have_accounts {
+                if state.accounts.is_empty() {
                     return false;
                 }
 
                 from.get(&state.accounts).id != to.get(&state.accounts).id
+                    && is_active(from)
+                    && is_active(to)
+            }
+            Transition::Reserve { account_id, .. } => {
+                !state.accounts.is_empty() && is_active(account_id)
+            }
+            Transition::Unreserve { account_id, .. } => {
+                !state.accounts.is_empty() && is_active(account_id)
+            }
+            Transition::SetLock { .. } => !state.accounts.is_empty(),
+            Transition::RemoveLock { .. } => !state.accounts.is_empty(),
+            Transition::Mint { account_id, .. } => {
+                !state.accounts.is_empty() && is_active(account_id)
             }
+            Transition::Burn { account_id, .. } => {
+                !state.accounts.is_empty() && is_active(account_id)
+            }
+            Transition::Freeze { .. } => !state.accounts.is_empty(),
+            Transition::Unfreeze { .. } => !state.accounts.is_empty(),
+            Transition::Close { .. } => !state.accounts.is_empty(),
         }
     }
 }
 
+/// Whether withdrawing `amount` from an account with `prev_free` free
+/// balance would overdraw it under the model's policy, mirroring
+/// `Bank::can_withdraw`'s reducible-balance check.
+fn would_overdraw(sim: &SimAccount, prev_free: i64, amount: u64) -> bool {
+    !sim.can_overdraw && prev_free.saturating_sub(saturating_i64(amount)) < sim.locked()
+}
+
 impl StateMachineTest for RealBank {
     type SystemUnderTest = Self;
     type Reference = SimBank;
@@ -167,15 +484,24 @@ impl StateMachineTest for RealBank {
     ) -> Self::SystemUnderTest {
         // Apply transition. This is the "act" part of our test.
         match transition {
-            Transition::Open => {
-                let id = state.inner.open(false).unwrap();
+            Transition::Open { can_overdraw } => {
+                let id = state.inner.open(can_overdraw).unwrap();
 
                 state.open_accounts.push(id);
             }
             Transition::Withdraw { account_id, amount } => {
-                let account = account_id.get(&state.open_accounts);
+                let account = *account_id.get(&state.open_accounts);
+                let sim_account = account_id.get(&ref_state.accounts);
+                let prev_free = state.inner.free_balance(account).unwrap();
+                let would_overdraw = would_overdraw(sim_account, prev_free, amount);
 
-                state.inner.withdraw(*account, amount).unwrap();
+                let result = state.inner.withdraw(account, amount);
+
+                if would_overdraw {
+                    result.expect_err("real bank should reject an overdrawing withdrawal");
+                } else {
+                    result.expect("real bank should accept a withdrawal the model allows");
+                }
             }
             Transition::Deposit { account_id, amount } => {
                 let account = account_id.get(&state.open_accounts);
@@ -183,10 +509,124 @@ impl StateMachineTest for RealBank {
                 state.inner.deposit(*account, amount).unwrap();
             }
             Transition::Transfer { from, to, amount } => {
-                let from = from.get(&state.open_accounts);
-                let to = to.get(&state.open_accounts);
+                let from_id = *from.get(&state.open_accounts);
+                let to_id = *to.get(&state.open_accounts);
+                let from_sim = from.get(&ref_state.accounts);
+                let prev_free = state.inner.free_balance(from_id).unwrap();
+                let would_overdraw = would_overdraw(from_sim, prev_free, amount);
+
+                let result = state.inner.transfer(from_id, to_id, amount);
+
+                if would_overdraw {
+                    result.expect_err("real bank should reject an overdrawing transfer");
+                } else {
+                    result.expect("real bank should accept a transfer the model allows");
+                }
+            }
+            Transition::Reserve { account_id, amount } => {
+                let account = *account_id.get(&state.open_accounts);
+                let sim_account = account_id.get(&ref_state.accounts);
+                let prev_free = state.inner.free_balance(account).unwrap();
+                let would_overdraw = would_overdraw(sim_account, prev_free, amount);
+
+                let result = state.inner.reserve(account, amount);
+
+                if would_overdraw {
+                    result.expect_err("real bank should reject reserving into locked/overdrawn funds");
+                } else {
+                    result.expect("real bank should accept a reserve the model allows");
+                }
+            }
+            Transition::Unreserve { account_id, amount } => {
+                let account = *account_id.get(&state.open_accounts);
+                let prev_reserved = state.inner.reserved_balance(account).unwrap();
+                let would_fail = saturating_i64(amount) > prev_reserved;
+
+                let result = state.inner.unreserve(account, amount);
+
+                if would_fail {
+                    result.expect_err("real bank should reject unreserving more than is reserved");
+                } else {
+                    result.expect("real bank should accept an unreserve the model allows");
+                }
+            }
+            Transition::SetLock {
+                account_id,
+                lock_id,
+                amount,
+            } => {
+                let account = *account_id.get(&state.open_accounts);
+
+                state.inner.set_lock(account, lock_id, amount).unwrap();
+            }
+            Transition::RemoveLock {
+                account_id,
+                lock_id,
+            } => {
+                let account = *account_id.get(&state.open_accounts);
+
+                state.inner.remove_lock(account, lock_id).unwrap();
+            }
+            Transition::Mint { account_id, amount } => {
+                let account = *account_id.get(&state.open_accounts);
 
-                state.inner.transfer(*from, *to, amount).unwrap();
+                state.inner.mint(account, amount).unwrap();
+            }
+            Transition::Burn { account_id, amount } => {
+                let account = *account_id.get(&state.open_accounts);
+                let sim_account = account_id.get(&ref_state.accounts);
+                let prev_free = state.inner.free_balance(account).unwrap();
+                let would_overdraw = would_overdraw(sim_account, prev_free, amount);
+
+                let result = state.inner.burn(account, amount);
+
+                if would_overdraw {
+                    result.expect_err("real bank should reject burning into locked/overdrawn funds");
+                } else {
+                    result.expect("real bank should accept a burn the model allows");
+                }
+            }
+            Transition::Freeze { account_id } => {
+                let account = *account_id.get(&state.open_accounts);
+                let prev_status = state.inner.status(account).unwrap();
+                let should_succeed = prev_status == RealAccountStatus::Active;
+
+                let result = state.inner.freeze(account);
+
+                if should_succeed {
+                    result.expect("real bank should accept freezing an active account");
+                } else {
+                    result.expect_err("real bank should reject freezing a non-active account");
+                }
+            }
+            Transition::Unfreeze { account_id } => {
+                let account = *account_id.get(&state.open_accounts);
+                let prev_status = state.inner.status(account).unwrap();
+                let should_succeed = prev_status == RealAccountStatus::Frozen;
+
+                let result = state.inner.unfreeze(account);
+
+                if should_succeed {
+                    result.expect("real bank should accept unfreezing a frozen account");
+                } else {
+                    result.expect_err("real bank should reject unfreezing a non-frozen account");
+                }
+            }
+            Transition::Close { account_id } => {
+                let account = *account_id.get(&state.open_accounts);
+                let prev_status = state.inner.status(account).unwrap();
+                let prev_balance = state.inner.balance(account).unwrap();
+                let should_succeed = prev_status == RealAccountStatus::Active && prev_balance == 0;
+
+                let result = state.inner.close(account);
+
+                if should_succeed {
+                    result.expect("real bank should accept closing an empty active account");
+                } else {
+                    result.expect_err(
+                        "real bank should reject closing a non-active or non-empty account",
+                    );
+                }
             }
         }
 
@@ -198,14 +638,31 @@ impl StateMachineTest for RealBank {
 
         for (id, sim_account) in open_accounts.zip(sim_accounts) {
             let actual_balance = state.inner.balance(*id).unwrap();
-            let expected_balance = sim_account.balance;
+            let expected_balance = sim_account.free.saturating_add(sim_account.reserved);
 
             assert_eq!(
                 actual_balance, expected_balance,
                 "balance mismatch on account {id}"
             );
+
+            let actual_reserved = state.inner.reserved_balance(*id).unwrap();
+
+            assert_eq!(
+                actual_reserved, sim_account.reserved,
+                "reserved balance mismatch on account {id}"
+            );
         }
 
+        // The crate-wide invariant: total issuance only moves via mint/burn.
+        // It does *not* equal the sum of all balances -- deposits and
+        // withdrawals move money in and out of the model without minting or
+        // burning it, so that sum drifts independently of issuance.
+        assert_eq!(
+            state.inner.total_issuance(),
+            ref_state.total_issuance,
+            "total issuance should match the model"
+        );
+
         state
     }
 }